@@ -2,11 +2,43 @@
 
 #![warn(missing_docs, unused_variables, unused_crate_dependencies)]
 
+pub mod codec;
+
+use std::io::{Read, Write};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use thiserror::Error;
 
+/// HMAC-SHA256 used to answer a [`TunnelMessage::Challenge`] without
+/// ever sending the association token itself over the wire
+type HmacSha256 = Hmac<Sha256>;
+
 /// Current version of the protocol
 pub const VERSION: u8 = 1;
 
+/// Suggested block size in bytes to pass to [`MessageSerializer::with_padding`]
+/// for padding [`TunnelMessage::Forward`] payloads, to resist traffic analysis
+/// of forwarded game packet sizes. The padding code itself uses whatever
+/// block size the caller passes and does not read this constant
+pub const PADDING_BLOCK: usize = 160; // 10*128/8
+
+/// Bit in a [`TunnelMessage::Forward`] flags byte indicating the payload
+/// was zlib compressed (see [`MessageSerializer::with_compression`])
+const FORWARD_FLAG_COMPRESSED: u8 = 0b0000_0001;
+
+/// Number of bytes a v2+ [`TunnelMessage::KeepAlive`] body occupies
+/// (`sequence: u32` + `timestamp: u64`), used to tell a genuine v1
+/// zero-payload `KeepAlive` apart from one carrying a real payload
+const KEEP_ALIVE_BODY_LEN: usize = 4 + 8;
+
+/// Maximum decompressed size accepted for a [`TunnelMessage::Forward`]
+/// payload, independent of the attacker-supplied `original_len` on the
+/// wire, so a truthful but huge claim can't be used to turn a small
+/// compressed frame into an outsized allocation/decompression (zip bomb)
+const MAX_FORWARD_PAYLOAD_LEN: usize = 64 * 1024;
+
 /// Individual tunnel message packet, includes the packet header
 /// and the message
 #[derive(Debug)]
@@ -18,44 +50,82 @@ pub struct TunnelPacket {
 }
 
 impl TunnelPacket {
-    /// Reads a tunnel packet from the provided deserializer
+    /// Reads a tunnel packet from the provided deserializer, verifying the
+    /// trailing CRC32 footer covers the header and body bytes that were read
     pub fn read(read: &mut MessageDeserializer<'_>) -> Result<TunnelPacket, MessageError> {
         let header = TunnelMessageHeader::read(read)?;
-        let message = TunnelMessage::read(read)?;
+        let message = TunnelMessage::read(read, header.version)?;
+
+        let checksum = crc32fast::hash(read.consumed());
+        let expected_checksum = read.read_u32()?;
+        if checksum != expected_checksum {
+            return Err(MessageError::ChecksumMismatch);
+        }
 
         Ok(Self { header, message })
     }
 
-    /// Writes the tunnel packet to the provided serializer
+    /// Writes the tunnel packet to the provided serializer, appending a
+    /// trailing CRC32 computed over the header and body bytes just written
     pub fn write(&self, write: &mut MessageSerializer) {
         self.header.write(write);
-        self.message.write(write);
+        self.message.write(write, self.header.version);
+
+        let checksum = crc32fast::hash(write.buffer());
+        write.write_u32(checksum);
     }
 }
 
 /// Serializes the provided message into byte form, uses the provided
-/// `tunnel_id` as the tunnel ID in the message header
-pub fn serialize_message(tunnel_id: u32, message: &TunnelMessage) -> Vec<u8> {
+/// `tunnel_id` as the tunnel ID and `version` as the negotiated protocol
+/// version in the message header. A trailing CRC32 is appended over the
+/// header and body so the receiver can detect corruption
+///
+/// `padding`/`compression` are forwarded to [`MessageSerializer::with_padding`]/
+/// [`MessageSerializer::with_compression`] so a caller doesn't have to
+/// hand-roll a [`MessageSerializer`] and [`TunnelPacket::write`] themselves
+/// just to reach those options
+pub fn serialize_message(
+    tunnel_id: u32,
+    version: u8,
+    message: &TunnelMessage,
+    padding: Option<usize>,
+    compression: Option<usize>,
+) -> Vec<u8> {
     let mut write = MessageSerializer::default();
-    let header = TunnelMessageHeader {
-        version: VERSION,
-        tunnel_id,
-    };
+    if let Some(block) = padding {
+        write = write.with_padding(block);
+    }
+    if let Some(threshold) = compression {
+        write = write.with_compression(threshold);
+    }
+
+    let header = TunnelMessageHeader { version, tunnel_id };
 
     header.write(&mut write);
-    message.write(&mut write);
+    message.write(&mut write, version);
+
+    let checksum = crc32fast::hash(write.buffer());
+    write.write_u32(checksum);
 
     write.into_inner()
 }
 
-/// Deserializes a header and a message from the provided buffer
-pub fn deserialize_message(buffer: &[u8]) -> Result<TunnelPacket, MessageError> {
+/// Deserializes a header and a message from the provided buffer, verifying
+/// the trailing CRC32 footer before trusting any of the parsed contents
+///
+/// `padding` is forwarded to [`MessageDeserializer::with_padding`] so a
+/// caller doesn't have to hand-roll a [`MessageDeserializer`] and
+/// [`TunnelPacket::read`] themselves just to reach that option
+pub fn deserialize_message(
+    buffer: &[u8],
+    padding: Option<usize>,
+) -> Result<TunnelPacket, MessageError> {
     let mut read = MessageDeserializer::new(buffer);
-
-    let header = TunnelMessageHeader::read(&mut read)?;
-    let message = TunnelMessage::read(&mut read)?;
-
-    Ok(TunnelPacket { header, message })
+    if let Some(block) = padding {
+        read = read.with_padding(block);
+    }
+    TunnelPacket::read(&mut read)
 }
 
 /// Writer for serializing various data types into a
@@ -64,9 +134,33 @@ pub fn deserialize_message(buffer: &[u8]) -> Result<TunnelPacket, MessageError>
 pub struct MessageSerializer {
     /// Buffer bytes are serialized into
     buffer: Vec<u8>,
+    /// Block size to pad [`TunnelMessage::Forward`] payloads up to, if enabled
+    padding: Option<usize>,
+    /// Minimum [`TunnelMessage::Forward`] payload size that gets zlib
+    /// compressed, if enabled
+    compression: Option<usize>,
 }
 
 impl MessageSerializer {
+    /// Enables padding of [`TunnelMessage::Forward`] payloads up to the next
+    /// multiple of `block` bytes, to resist traffic analysis of forwarded
+    /// game packet sizes at the cost of some bandwidth overhead
+    ///
+    /// A `block` of 0 is treated as padding disabled, since it has no
+    /// well-defined multiple to round up to
+    pub fn with_padding(mut self, block: usize) -> Self {
+        self.padding = (block > 0).then_some(block);
+        self
+    }
+
+    /// Enables zlib compression of [`TunnelMessage::Forward`] payloads
+    /// larger than `threshold` bytes, so small latency-sensitive packets
+    /// never pay the compression overhead
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compression = Some(threshold);
+        self
+    }
+
     /// Writes a byte to the buffer
     #[inline]
     pub fn write_u8(&mut self, value: u8) {
@@ -89,6 +183,11 @@ impl MessageSerializer {
         self.write_bytes(&value.to_be_bytes())
     }
 
+    /// Writes a 64bit unsigned int to the buffer
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_bytes(&value.to_be_bytes())
+    }
+
     /// Gets a slice of the underlying buffer
     pub fn buffer(&self) -> &[u8] {
         &self.buffer
@@ -98,6 +197,23 @@ impl MessageSerializer {
     pub fn into_inner(self) -> Vec<u8> {
         self.buffer
     }
+
+    /// Writes a length-prefixed payload, padding it up to the next multiple
+    /// of the configured block size when padding is enabled (see
+    /// [`MessageSerializer::with_padding`]). The true length is always
+    /// written first so [`MessageDeserializer::read_payload`] can discard
+    /// the padding again
+    fn write_payload(&mut self, value: &[u8]) {
+        debug_assert!(value.len() < u16::MAX as usize);
+        self.write_u16(value.len() as u16);
+        self.write_bytes(value);
+
+        if let Some(block) = self.padding {
+            let padded_len = ((value.len() / block) + 1) * block;
+            self.buffer
+                .resize(self.buffer.len() + (padded_len - value.len()), 0);
+        }
+    }
 }
 
 /// Reader for deserializing various data types from a
@@ -107,12 +223,28 @@ pub struct MessageDeserializer<'a> {
     buffer: &'a [u8],
     /// Current position within the buffer we have read up to
     cursor: usize,
+    /// Block size that padded payloads were written with, if enabled
+    padding: Option<usize>,
 }
 
 impl<'a> MessageDeserializer<'a> {
     /// Creates a new deserializer from the buffer
     pub fn new(buffer: &'a [u8]) -> MessageDeserializer<'a> {
-        MessageDeserializer { buffer, cursor: 0 }
+        MessageDeserializer {
+            buffer,
+            cursor: 0,
+            padding: None,
+        }
+    }
+
+    /// Enables discarding of payload padding written with
+    /// [`MessageSerializer::with_padding`] using the same block size
+    ///
+    /// A `block` of 0 is treated as padding disabled, matching
+    /// [`MessageSerializer::with_padding`]
+    pub fn with_padding(mut self, block: usize) -> Self {
+        self.padding = (block > 0).then_some(block);
+        self
     }
 
     /// Gets the total capacity of the underlying buffer
@@ -170,6 +302,13 @@ impl<'a> MessageDeserializer<'a> {
         Ok(value)
     }
 
+    /// Reads a 64bit unsigned integer from the buffer
+    pub fn read_u64(&mut self) -> Result<u64, MessageError> {
+        let value: [u8; 8] = self.read_fixed()?;
+        let value = u64::from_be_bytes(value);
+        Ok(value)
+    }
+
     /// Reads a runtime known length of bytes from the buffer
     pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], MessageError> {
         if self.len() < length {
@@ -180,13 +319,37 @@ impl<'a> MessageDeserializer<'a> {
         self.cursor += length;
         Ok(value)
     }
+
+    /// Reads a length-prefixed payload written with
+    /// [`MessageSerializer::write_payload`], discarding any block padding
+    /// when enabled (see [`MessageDeserializer::with_padding`])
+    fn read_payload(&mut self) -> Result<Vec<u8>, MessageError> {
+        let real_len = self.read_u16()? as usize;
+
+        match self.padding {
+            Some(block) => {
+                let padded_len = ((real_len / block) + 1) * block;
+                let bytes = self.read_bytes(padded_len)?;
+                Ok(bytes[..real_len].to_vec())
+            }
+            None => Ok(self.read_bytes(real_len)?.to_vec()),
+        }
+    }
+
+    /// Bytes read so far, used to verify a trailing checksum covers
+    /// exactly what has been parsed
+    fn consumed(&self) -> &'a [u8] {
+        &self.buffer[..self.cursor]
+    }
 }
 
 /// Header before a tunnel message indicating the protocol version
 /// and ID of the tunnel
 #[derive(Debug)]
 pub struct TunnelMessageHeader {
-    /// Protocol version (For future sake)
+    /// Protocol version negotiated for this tunnel via
+    /// [`TunnelMessage::negotiate_version`], threaded through
+    /// [`TunnelMessage::read`]/[`TunnelMessage::write`]
     pub version: u8,
     /// ID of the tunnel this message is from, [u32::MAX] when the
     /// tunnel is not yet initiated
@@ -203,11 +366,54 @@ pub enum MessageError {
     /// Message didn't have enough bytes to fully parse
     #[error("message wasn't long enough to read {0} bytes")]
     Incomplete(usize),
+
+    /// Ack byte didn't match a known [`Ack`] variant
+    #[error("unknown ack value")]
+    UnknownAck,
+
+    /// A compressed `Forward` payload failed to decompress, or decompressed
+    /// to something other than its advertised original length
+    #[error("payload failed to decompress")]
+    DecompressionFailed,
+
+    /// None of a client's advertised `supported_versions` intersected the
+    /// server's supported protocol versions
+    #[error("no overlapping protocol version")]
+    UnsupportedVersion,
+
+    /// Header's magic bytes didn't match, likely internet background noise
+    /// or a spoofed packet rather than a real tunnel message
+    #[error("bad magic bytes")]
+    BadMagic,
+
+    /// Trailing CRC32 didn't match the computed checksum of the packet,
+    /// indicating in-flight corruption or a truncated/spoofed datagram
+    #[error("checksum mismatch")]
+    ChecksumMismatch,
+
+    /// A framed message's advertised length exceeded the maximum a stream
+    /// transport will buffer for a single frame
+    #[error("frame exceeds maximum allowed size")]
+    FrameTooLarge,
+
+    /// Underlying IO error while reading or writing a framed message
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
 
+/// Magic bytes prefixed to every [`TunnelMessageHeader`], letting
+/// [`TunnelMessageHeader::read`] cheaply reject stray internet-background-noise
+/// packets before any length field in the body is trusted
+const MAGIC: [u8; 4] = *b"PRUT";
+
 impl TunnelMessageHeader {
     /// Reads a tunnel message header from the provided deserializer
     pub fn read(buf: &mut MessageDeserializer<'_>) -> Result<TunnelMessageHeader, MessageError> {
+        let magic = buf.read_fixed::<4>()?;
+        if magic != MAGIC {
+            return Err(MessageError::BadMagic);
+        }
+
         let version = buf.read_u8()?;
         let tunnel_id = buf.read_u32()?;
 
@@ -216,6 +422,7 @@ impl TunnelMessageHeader {
 
     /// Writes the tunnel message header to the provided serializer
     pub fn write(&self, buf: &mut MessageSerializer) {
+        buf.write_bytes(&MAGIC);
         buf.write_u8(self.version);
         buf.write_u32(self.tunnel_id);
     }
@@ -225,19 +432,34 @@ impl TunnelMessageHeader {
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[repr(u8)]
 pub enum TunnelMessageType {
-    /// Client is requesting to initiate a connection
+    /// Client is requesting to initiate a connection, advertising
+    /// the key/session id it wants to authenticate with
     Initiate = 0x0,
 
-    /// Server has accepted a connection
+    /// Server has accepted (or rejected) a connection
     Initiated = 0x1,
 
     /// Forward a message on behalf of the player to
     /// another player
     Forward = 0x2,
 
-    /// Message to keep the stream alive
-    /// (When the connect is inactive)
+    /// Ping to keep the stream alive (when the connection is inactive)
+    /// and measure round-trip time, answered with a
+    /// [`TunnelMessageType::KeepAliveAck`]
     KeepAlive = 0x3,
+
+    /// Server challenges the client with a single-use nonce to
+    /// authenticate before a tunnel is associated
+    Challenge = 0x4,
+
+    /// Client answers a [`TunnelMessageType::Challenge`] with a digest
+    /// proving it holds the association token, without ever sending
+    /// the token itself
+    AuthResponse = 0x5,
+
+    /// Pong answering a [`TunnelMessageType::KeepAlive`], echoing back
+    /// its sequence number and timestamp unchanged
+    KeepAliveAck = 0x6,
 }
 
 impl TryFrom<u8> for TunnelMessageType {
@@ -249,25 +471,104 @@ impl TryFrom<u8> for TunnelMessageType {
             0x1 => Self::Initiated,
             0x2 => Self::Forward,
             0x3 => Self::KeepAlive,
+            0x4 => Self::Challenge,
+            0x5 => Self::AuthResponse,
+            0x6 => Self::KeepAliveAck,
             _ => return Err(MessageError::UnknownMessageType),
         })
     }
 }
 
+/// Outcome of a tunnel association/authentication attempt, reported back
+/// in [`TunnelMessage::Initiated`] instead of silently dropping the client
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+pub enum Ack {
+    /// Tunnel was associated and authenticated successfully
+    Ok = 0,
+
+    /// The advertised key/session id was not recognized
+    Unknown = 1,
+
+    /// The challenge digest did not match the expected association token
+    AuthFailed = 2,
+}
+
+impl TryFrom<u8> for Ack {
+    type Error = MessageError;
+
+    fn try_from(value: u8) -> Result<Self, MessageError> {
+        Ok(match value {
+            0 => Self::Ok,
+            1 => Self::Unknown,
+            2 => Self::AuthFailed,
+            _ => return Err(MessageError::UnknownAck),
+        })
+    }
+}
+
+impl Ack {
+    /// Reads an ack from the provided deserializer
+    pub fn read(read: &mut MessageDeserializer<'_>) -> Result<Ack, MessageError> {
+        let value = read.read_u8()?;
+        Ack::try_from(value)
+    }
+
+    /// Writes the ack to the provided serializer
+    pub fn write(&self, write: &mut MessageSerializer) {
+        write.write_u8(*self as u8);
+    }
+}
+
 /// Variants of the tunnel body
 #[derive(Debug)]
 pub enum TunnelMessage {
-    /// Client is requesting to initiate a connection
+    /// Client is requesting to initiate a connection, advertising which
+    /// key/session id it wants to authenticate with. The association
+    /// token itself is never sent here, only answered in a
+    /// [`TunnelMessage::AuthResponse`] once challenged
     Initiate {
-        /// Association token to authenticate with
-        association_token: String,
+        /// Identifier for the association-token keyed material the
+        /// client wants to authenticate with
+        key_id: String,
+
+        /// Protocol versions the client is able to speak. Their order is
+        /// not significant: [`TunnelMessage::negotiate_version`] picks the
+        /// highest version both sides support
+        supported_versions: Vec<u8>,
     },
 
-    /// Server created and associated the tunnel
+    /// Server has accepted (or rejected) the tunnel
     Initiated {
         /// Unique ID for the tunnel to include in future messages
-        /// to identify itself
+        /// to identify itself, meaningless unless `ack` is [`Ack::Ok`]
         tunnel_id: u32,
+
+        /// Outcome of the authentication attempt
+        ack: Ack,
+
+        /// Protocol version the server has chosen to speak for this
+        /// tunnel, picked via [`TunnelMessage::negotiate_version`]
+        chosen_version: u8,
+    },
+
+    /// Server challenges the client to prove it holds the association
+    /// token for the `key_id` it advertised in [`TunnelMessage::Initiate`]
+    Challenge {
+        /// Single-use random nonce the client must sign
+        nonce: [u8; 32],
+    },
+
+    /// Client answer to a [`TunnelMessage::Challenge`], proving it holds
+    /// the association token without ever sending it
+    AuthResponse {
+        /// Identifier for the association-token keyed material used
+        /// to sign the challenge
+        key_id: String,
+
+        /// `HMAC-SHA256(association_token, nonce)` computed with
+        /// [`TunnelMessage::compute_challenge_digest`]
+        digest: [u8; 32],
     },
 
     /// Client wants to forward a message
@@ -280,13 +581,42 @@ pub enum TunnelMessage {
         message: Vec<u8>,
     },
 
-    /// Keep alive
-    KeepAlive,
+    /// Ping sent to keep the stream alive and measure round-trip time.
+    /// A v1 peer may send this with no payload, which is read back as
+    /// `sequence: 0, timestamp: 0` for backward compatibility
+    KeepAlive {
+        /// Monotonically increasing sequence number, used by the sender
+        /// to spot reordering or loss of its keep-alives
+        sequence: u32,
+
+        /// Sender's local time in milliseconds when this was sent, echoed
+        /// back unchanged in the [`TunnelMessage::KeepAliveAck`] so the
+        /// sender can compute round-trip time
+        timestamp: u64,
+    },
+
+    /// Pong answering a [`TunnelMessage::KeepAlive`], echoing its
+    /// `sequence` and `timestamp` back unchanged
+    KeepAliveAck {
+        /// Sequence number echoed back from the [`TunnelMessage::KeepAlive`]
+        sequence: u32,
+
+        /// Timestamp echoed back from the [`TunnelMessage::KeepAlive`]
+        timestamp: u64,
+    },
 }
 
 impl TunnelMessage {
     /// Reads a tunnel message from the provided deserializer
-    pub fn read(read: &mut MessageDeserializer<'_>) -> Result<TunnelMessage, MessageError> {
+    ///
+    /// `version` is the protocol version already negotiated for this tunnel
+    /// (see [`TunnelMessage::negotiate_version`]), threaded through so later
+    /// protocol revisions can change the `Forward`/`KeepAlive` wire format
+    /// without breaking v1 peers
+    pub fn read(
+        read: &mut MessageDeserializer<'_>,
+        version: u8,
+    ) -> Result<TunnelMessage, MessageError> {
         // Read the message type byte
         let ty = read.read_u8()?;
 
@@ -295,63 +625,381 @@ impl TunnelMessage {
 
         match ty {
             TunnelMessageType::Initiate => {
-                // Determine token length
+                // Determine key id length
                 let length = read.read_u16()? as usize;
 
-                // Read token bytes and construct string
-                let token_bytes = read.read_bytes(length)?;
-                let token = String::from_utf8_lossy(token_bytes);
+                // Read key id bytes and construct string
+                let key_id_bytes = read.read_bytes(length)?;
+                let key_id = String::from_utf8_lossy(key_id_bytes);
+
+                let version_count = read.read_u8()? as usize;
+                let supported_versions = read.read_bytes(version_count)?.to_vec();
 
                 Ok(TunnelMessage::Initiate {
-                    association_token: token.to_string(),
+                    key_id: key_id.to_string(),
+                    supported_versions,
                 })
             }
             TunnelMessageType::Initiated => {
                 let tunnel_id = read.read_u32()?;
-                Ok(TunnelMessage::Initiated { tunnel_id })
+                let ack = Ack::read(read)?;
+                let chosen_version = read.read_u8()?;
+                Ok(TunnelMessage::Initiated {
+                    tunnel_id,
+                    ack,
+                    chosen_version,
+                })
+            }
+            TunnelMessageType::Challenge => {
+                let nonce = read.read_fixed::<32>()?;
+                Ok(TunnelMessage::Challenge { nonce })
+            }
+            TunnelMessageType::AuthResponse => {
+                // Determine key id length
+                let length = read.read_u16()? as usize;
+
+                // Read key id bytes and construct string
+                let key_id_bytes = read.read_bytes(length)?;
+                let key_id = String::from_utf8_lossy(key_id_bytes);
+
+                let digest = read.read_fixed::<32>()?;
+
+                Ok(TunnelMessage::AuthResponse {
+                    key_id: key_id.to_string(),
+                    digest,
+                })
             }
             TunnelMessageType::Forward => {
+                // No version-specific Forward fields yet; `version` is
+                // threaded through for later protocol revisions
+                debug_assert_eq!(version, VERSION);
+
+                let flags = read.read_u8()?;
                 let index = read.read_u8()?;
 
-                // Get length of the association token
-                let length = read.read_u16()? as usize;
+                let message = if flags & FORWARD_FLAG_COMPRESSED != 0 {
+                    let original_len = read.read_u32()? as usize;
+                    let compressed_len = read.read_u16()? as usize;
+                    let compressed = read.read_bytes(compressed_len)?;
+                    decompress_payload(compressed, original_len)?
+                } else {
+                    read.read_payload()?
+                };
 
-                let message = read.read_bytes(length)?;
+                Ok(TunnelMessage::Forward { index, message })
+            }
+            TunnelMessageType::KeepAlive => {
+                // `version` is threaded through for later protocol revisions
+                debug_assert_eq!(version, VERSION);
+
+                // A v1 peer sends a zero-payload KeepAlive, read that back
+                // as sequence 0 instead of erroring. `read` still has the
+                // trailing CRC32 footer left to consume even for a v1
+                // peer, so a genuine zero-payload KeepAlive is never
+                // actually empty here - it just doesn't have a full body
+                if read.len() < KEEP_ALIVE_BODY_LEN {
+                    return Ok(TunnelMessage::KeepAlive {
+                        sequence: 0,
+                        timestamp: 0,
+                    });
+                }
+
+                let sequence = read.read_u32()?;
+                let timestamp = read.read_u64()?;
+
+                Ok(TunnelMessage::KeepAlive {
+                    sequence,
+                    timestamp,
+                })
+            }
+            TunnelMessageType::KeepAliveAck => {
+                let sequence = read.read_u32()?;
+                let timestamp = read.read_u64()?;
 
-                Ok(TunnelMessage::Forward {
-                    index,
-                    message: message.to_vec(),
+                Ok(TunnelMessage::KeepAliveAck {
+                    sequence,
+                    timestamp,
                 })
             }
-            TunnelMessageType::KeepAlive => Ok(TunnelMessage::KeepAlive),
         }
     }
 
     /// Writes the tunnel message to the provided serializer
-    pub fn write(&self, write: &mut MessageSerializer) {
+    ///
+    /// `version` is the protocol version negotiated for this tunnel, see
+    /// [`TunnelMessage::read`]
+    pub fn write(&self, write: &mut MessageSerializer, version: u8) {
         match self {
-            TunnelMessage::Initiate { association_token } => {
-                debug_assert!(association_token.len() < u16::MAX as usize);
+            TunnelMessage::Initiate {
+                key_id,
+                supported_versions,
+            } => {
+                debug_assert!(key_id.len() < u16::MAX as usize);
+                debug_assert!(supported_versions.len() < u8::MAX as usize);
                 write.write_u8(TunnelMessageType::Initiate as u8);
 
-                write.write_u16(association_token.len() as u16);
-                write.write_bytes(association_token.as_bytes());
+                write.write_u16(key_id.len() as u16);
+                write.write_bytes(key_id.as_bytes());
+
+                write.write_u8(supported_versions.len() as u8);
+                write.write_bytes(supported_versions);
             }
-            TunnelMessage::Initiated { tunnel_id } => {
+            TunnelMessage::Initiated {
+                tunnel_id,
+                ack,
+                chosen_version,
+            } => {
                 write.write_u8(TunnelMessageType::Initiated as u8);
                 write.write_u32(*tunnel_id);
+                ack.write(write);
+                write.write_u8(*chosen_version);
+            }
+            TunnelMessage::Challenge { nonce } => {
+                write.write_u8(TunnelMessageType::Challenge as u8);
+                write.write_bytes(nonce);
+            }
+            TunnelMessage::AuthResponse { key_id, digest } => {
+                write.write_u8(TunnelMessageType::AuthResponse as u8);
+                debug_assert!(key_id.len() < u16::MAX as usize);
+
+                write.write_u16(key_id.len() as u16);
+                write.write_bytes(key_id.as_bytes());
+                write.write_bytes(digest);
             }
             TunnelMessage::Forward { index, message } => {
+                // No version-specific Forward fields yet; `version` is
+                // threaded through for later protocol revisions
+                debug_assert_eq!(version, VERSION);
+
                 write.write_u8(TunnelMessageType::Forward as u8);
-                debug_assert!(message.len() < u16::MAX as usize);
 
-                write.write_u8(*index);
-                write.write_u16(message.len() as u16);
-                write.write_bytes(message);
+                // Only actually use the compressed form if it both shrank
+                // the payload and fits the u16 compressed-length prefix;
+                // an incompressible payload (e.g. already-encrypted game
+                // traffic) can come out of zlib larger than it went in
+                // once the stored-block + wrapper overhead is added
+                let compressed = write
+                    .compression
+                    .filter(|&threshold| message.len() > threshold)
+                    .map(|_| compress_payload(message))
+                    .filter(|compressed| {
+                        compressed.len() < message.len() && compressed.len() < u16::MAX as usize
+                    });
+
+                match compressed {
+                    Some(compressed) => {
+                        write.write_u8(FORWARD_FLAG_COMPRESSED);
+                        write.write_u8(*index);
+                        write.write_u32(message.len() as u32);
+                        write.write_u16(compressed.len() as u16);
+                        write.write_bytes(&compressed);
+                    }
+                    None => {
+                        write.write_u8(0);
+                        write.write_u8(*index);
+                        write.write_payload(message);
+                    }
+                }
             }
-            TunnelMessage::KeepAlive => {
+            TunnelMessage::KeepAlive {
+                sequence,
+                timestamp,
+            } => {
+                // `version` is threaded through for later protocol revisions
+                debug_assert_eq!(version, VERSION);
+
                 write.write_u8(TunnelMessageType::KeepAlive as u8);
+                write.write_u32(*sequence);
+                write.write_u64(*timestamp);
+            }
+            TunnelMessage::KeepAliveAck {
+                sequence,
+                timestamp,
+            } => {
+                write.write_u8(TunnelMessageType::KeepAliveAck as u8);
+                write.write_u32(*sequence);
+                write.write_u64(*timestamp);
+            }
+        }
+    }
+
+    /// Computes the digest a client must produce to answer a
+    /// [`TunnelMessage::Challenge`], as `HMAC-SHA256(association_token, nonce)`
+    pub fn compute_challenge_digest(association_token: &[u8], nonce: &[u8; 32]) -> [u8; 32] {
+        let mut mac = HmacSha256::new_from_slice(association_token)
+            .expect("HMAC can be created with a key of any size");
+        mac.update(nonce);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Verifies a [`TunnelMessage::AuthResponse`] digest against the
+    /// expected association token and nonce in constant time
+    pub fn verify_challenge_digest(
+        association_token: &[u8],
+        nonce: &[u8; 32],
+        digest: &[u8; 32],
+    ) -> bool {
+        let mut mac = HmacSha256::new_from_slice(association_token)
+            .expect("HMAC can be created with a key of any size");
+        mac.update(nonce);
+        mac.verify_slice(digest).is_ok()
+    }
+
+    /// Picks the protocol version to speak with a client from the
+    /// `supported_versions` it advertised in [`TunnelMessage::Initiate`],
+    /// preferring the highest version both sides support
+    ///
+    /// Returns [`MessageError::UnsupportedVersion`] if none of the client's
+    /// supported versions are also supported by the server
+    pub fn negotiate_version(
+        supported_versions: &[u8],
+        server_versions: &[u8],
+    ) -> Result<u8, MessageError> {
+        server_versions
+            .iter()
+            .filter(|version| supported_versions.contains(version))
+            .max()
+            .copied()
+            .ok_or(MessageError::UnsupportedVersion)
+    }
+}
+
+/// Zlib-compresses a `Forward` payload for [`TunnelMessage::write`]
+fn compress_payload(value: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(value)
+        .expect("writing to an in-memory buffer cannot fail");
+    encoder
+        .finish()
+        .expect("writing to an in-memory buffer cannot fail")
+}
+
+/// Inflates a compressed `Forward` payload for [`TunnelMessage::read`],
+/// capping the amount read at `original_len` to avoid decompression bombs.
+/// `original_len` itself is attacker-supplied, so it's also rejected
+/// outright above [`MAX_FORWARD_PAYLOAD_LEN`] before any inflating starts -
+/// otherwise a truthful but huge claim would sail through the later
+/// length check unhindered
+fn decompress_payload(value: &[u8], original_len: usize) -> Result<Vec<u8>, MessageError> {
+    if original_len > MAX_FORWARD_PAYLOAD_LEN {
+        return Err(MessageError::DecompressionFailed);
+    }
+
+    let mut decoder = ZlibDecoder::new(value).take(original_len as u64);
+    let mut decompressed = Vec::new();
+    decoder
+        .read_to_end(&mut decompressed)
+        .map_err(|_| MessageError::DecompressionFailed)?;
+
+    if decompressed.len() != original_len {
+        return Err(MessageError::DecompressionFailed);
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_digest_round_trips_for_the_correct_token() {
+        let association_token = b"some-association-token";
+        let nonce = [7u8; 32];
+
+        let digest = TunnelMessage::compute_challenge_digest(association_token, &nonce);
+
+        assert!(TunnelMessage::verify_challenge_digest(
+            association_token,
+            &nonce,
+            &digest
+        ));
+    }
+
+    #[test]
+    fn challenge_digest_is_rejected_for_the_wrong_token() {
+        let nonce = [7u8; 32];
+        let digest = TunnelMessage::compute_challenge_digest(b"correct-token", &nonce);
+
+        assert!(!TunnelMessage::verify_challenge_digest(
+            b"wrong-token",
+            &nonce,
+            &digest
+        ));
+    }
+
+    fn round_trip(message: TunnelMessage) -> TunnelMessage {
+        let bytes = serialize_message(1, VERSION, &message, None, None);
+        deserialize_message(&bytes, None)
+            .expect("round-tripped message should deserialize")
+            .message
+    }
+
+    #[test]
+    fn initiate_round_trips() {
+        let message = TunnelMessage::Initiate {
+            key_id: "some-key-id".to_string(),
+            supported_versions: vec![1, 2],
+        };
+
+        match round_trip(message) {
+            TunnelMessage::Initiate {
+                key_id,
+                supported_versions,
+            } => {
+                assert_eq!(key_id, "some-key-id");
+                assert_eq!(supported_versions, vec![1, 2]);
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn challenge_round_trips() {
+        let message = TunnelMessage::Challenge { nonce: [9u8; 32] };
+
+        match round_trip(message) {
+            TunnelMessage::Challenge { nonce } => assert_eq!(nonce, [9u8; 32]),
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn auth_response_round_trips() {
+        let message = TunnelMessage::AuthResponse {
+            key_id: "some-key-id".to_string(),
+            digest: [3u8; 32],
+        };
+
+        match round_trip(message) {
+            TunnelMessage::AuthResponse { key_id, digest } => {
+                assert_eq!(key_id, "some-key-id");
+                assert_eq!(digest, [3u8; 32]);
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn initiated_round_trips() {
+        let message = TunnelMessage::Initiated {
+            tunnel_id: 42,
+            ack: Ack::Ok,
+            chosen_version: VERSION,
+        };
+
+        match round_trip(message) {
+            TunnelMessage::Initiated {
+                tunnel_id,
+                ack,
+                chosen_version,
+            } => {
+                assert_eq!(tunnel_id, 42);
+                assert_eq!(ack, Ack::Ok);
+                assert_eq!(chosen_version, VERSION);
             }
+            other => panic!("unexpected message: {other:?}"),
         }
     }
 }